@@ -27,7 +27,11 @@
 //! ```
 
 mod helper;
+mod readiness;
 mod runner;
+mod tls;
 
 pub use helper::with_postgres_ready;
+pub use readiness::Readiness;
 pub use runner::Runner;
+pub use tls::{Connector, TlsMode};
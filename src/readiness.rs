@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use dockertest::{waitfor::WaitFor, DockerTestError, RunningContainer};
+use futures::StreamExt;
+
+/// The log line postgres prints, on stderr, once it is ready to accept connections.
+const READY_MESSAGE: &str = "database system is ready to accept connections";
+
+/// Postgres prints [`READY_MESSAGE`] once for the short-lived bootstrap server it starts
+/// to run `initdb`, and again for the real server that stays up. [`Readiness::LogMessage`]
+/// waits for the second occurrence.
+const READY_OCCURRENCES: usize = 2;
+
+/// Selects how `Runner` decides the postgres container is ready to be polled for a connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Readiness {
+    /// Wait for the container to report running, then poll for a database connection.
+    ///
+    /// This is the default, but it can race against postgres's initial bootstrap: the
+    /// server briefly accepts connections on the init database before restarting, which
+    /// can let [`Runner`](crate::Runner) connect too early and have the connection dropped.
+    #[default]
+    Connection,
+    /// Wait for [`READY_MESSAGE`] to appear twice on stderr before polling for a connection.
+    /// See [`Readiness::Connection`] for why a single occurrence is not enough.
+    LogMessage,
+}
+
+/// A [`WaitFor`] that waits for [`READY_MESSAGE`] to appear [`READY_OCCURRENCES`] times on
+/// the container's stderr.
+#[derive(Clone)]
+pub(crate) struct LogMessageWait {
+    pub(crate) timeout: Duration,
+}
+
+#[dockertest::waitfor::async_trait]
+impl WaitFor for LogMessageWait {
+    async fn wait_for_ready(
+        &self,
+        running_container: RunningContainer,
+    ) -> Result<RunningContainer, DockerTestError> {
+        // `logs` takes (stdout, stderr); postgres prints its readiness banner to stderr.
+        const INCLUDE_STDOUT: bool = false;
+        const INCLUDE_STDERR: bool = true;
+
+        let mut seen = 0;
+        // Docker log chunks don't line up with log lines: a single chunk can carry more
+        // than one line (and thus more than one occurrence of `READY_MESSAGE`), and a
+        // line can be split across two chunks. Buffer and split on newlines so neither
+        // case under- or over-counts.
+        let mut buffer = String::new();
+        let mut logs = running_container
+            .logs(INCLUDE_STDOUT, INCLUDE_STDERR)
+            .await?;
+
+        tokio::time::timeout(self.timeout, async {
+            while seen < READY_OCCURRENCES {
+                let chunk = logs.next().await.ok_or_else(|| {
+                    DockerTestError::Startup(
+                        "Container stopped streaming logs before becoming ready".to_string(),
+                    )
+                })??;
+                seen += count_complete_lines(&mut buffer, &chunk, READY_MESSAGE);
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| {
+            DockerTestError::Startup(format!(
+                "Timed out waiting for \"{READY_MESSAGE}\" to appear {READY_OCCURRENCES} times"
+            ))
+        })??;
+
+        Ok(running_container)
+    }
+}
+
+/// Append `chunk` to `buffer`, then drain and count occurrences of `needle` across every
+/// complete (newline-terminated) line now in the buffer. Any trailing partial line is left
+/// in `buffer` for the next chunk.
+fn count_complete_lines(buffer: &mut String, chunk: &[u8], needle: &str) -> usize {
+    buffer.push_str(&String::from_utf8_lossy(chunk));
+
+    let mut count = 0;
+    while let Some(end) = buffer.find('\n') {
+        count += buffer[..end].matches(needle).count();
+        buffer.drain(..=end);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_multiple_occurrences_within_a_single_chunk() {
+        let mut buffer = String::new();
+        let chunk = b"ready\nready\n";
+        assert_eq!(count_complete_lines(&mut buffer, chunk, "ready"), 2);
+    }
+
+    #[test]
+    fn counts_an_occurrence_split_across_chunk_boundaries() {
+        let mut buffer = String::new();
+        assert_eq!(count_complete_lines(&mut buffer, b"rea", "ready"), 0);
+        assert_eq!(count_complete_lines(&mut buffer, b"dy\n", "ready"), 1);
+    }
+
+    #[test]
+    fn leaves_a_trailing_partial_line_buffered() {
+        let mut buffer = String::new();
+        assert_eq!(count_complete_lines(&mut buffer, b"ready\nnot ", "ready"), 1);
+        assert_eq!(buffer, "not ");
+    }
+}
@@ -1,28 +1,72 @@
 use std::{
+    fs,
     future::Future,
     panic::{self, UnwindSafe},
+    path::Path,
     time::Duration,
 };
 
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
 use dockertest::{waitfor::RunningWait, Composition, DockerTest, Image};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::Rng;
 use tokio::{runtime::Handle, task, time::sleep};
 
-const POSTGRES_PASSWORD: &str = "postgres";
+use crate::readiness::{LogMessageWait, Readiness};
+use crate::tls::{Connector, TlsMode};
+
+/// Shell command that generates a fresh self-signed certificate before handing
+/// control to the regular postgres entrypoint with TLS switched on.
+const ENABLE_TLS_CMD: &str = "\
+    openssl req -new -x509 -days 1 -nodes -text \
+    -subj '/CN=localhost' \
+    -out /var/lib/postgresql/server.crt -keyout /var/lib/postgresql/server.key \
+    && chmod 600 /var/lib/postgresql/server.key \
+    && chown postgres:postgres /var/lib/postgresql/server.key \
+    && exec docker-entrypoint.sh postgres \
+        -c ssl=on \
+        -c ssl_cert_file=/var/lib/postgresql/server.crt \
+        -c ssl_key_file=/var/lib/postgresql/server.key";
 
 pub struct Runner {
+    repository: &'static str,
     container_tag: &'static str,
     container_timeout: Duration,
     connection_timeout: Duration,
     connection_test_interval: Duration,
+    init_sql: Option<String>,
+    user: String,
+    password: String,
+    database: String,
+    tls: TlsMode,
+    readiness: Readiness,
+    pool_max_size: u32,
+    backoff_multiplier: f64,
+    max_backoff: Duration,
+    ready_port: u16,
+    extra_env: Vec<(String, String)>,
 }
 
 impl Default for Runner {
     fn default() -> Self {
         Self {
+            repository: "postgres",
             container_tag: "15.3-alpine3.18",
             container_timeout: Duration::from_secs(10),
             connection_timeout: Duration::from_secs(2),
             connection_test_interval: Duration::from_millis(100),
+            init_sql: None,
+            user: "postgres".to_string(),
+            password: "postgres".to_string(),
+            database: "postgres".to_string(),
+            tls: TlsMode::Disabled,
+            readiness: Readiness::Connection,
+            ready_port: 5432,
+            extra_env: Vec::new(),
+            pool_max_size: 10,
+            backoff_multiplier: 1.5,
+            max_backoff: Duration::from_millis(500),
         }
     }
 }
@@ -34,8 +78,17 @@ impl Runner {
         Default::default()
     }
 
-    /// Set the postgres image tag to use.
-    /// See <https://hub.docker.com/_/postgres> for available tags.
+    /// Set the Docker Hub repository of the image to run, e.g. `cockroachdb/cockroach`
+    /// for a Postgres-wire-compatible database other than postgres itself.
+    ///
+    /// Defaults to `postgres`.
+    pub fn image(mut self, repository: &'static str) -> Self {
+        self.repository = repository;
+        self
+    }
+
+    /// Set the image tag to use.
+    /// See <https://hub.docker.com/_/postgres> for available postgres tags.
     ///
     /// Defaults to `15.3-alpine3.18`.
     pub fn container_tag(mut self, container_tag: &'static str) -> Self {
@@ -43,6 +96,24 @@ impl Runner {
         self
     }
 
+    /// Set an extra environment variable on the container.
+    /// An escape hatch for images that need startup configuration beyond
+    /// `POSTGRES_USER`/`POSTGRES_PASSWORD`/`POSTGRES_DB`.
+    ///
+    /// Can be called multiple times to set multiple variables.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.extra_env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the port, inside the container, that the database listens on.
+    ///
+    /// Defaults to 5432.
+    pub fn ready_port(mut self, ready_port: u16) -> Self {
+        self.ready_port = ready_port;
+        self
+    }
+
     /// Set the container timeout for the test.
     /// The test will fail if the container is not ready within this time.
     ///
@@ -61,7 +132,9 @@ impl Runner {
         self
     }
 
-    /// Set the interval between connection attempts.
+    /// Set the initial delay between connection attempts.
+    /// The delay grows by [`Runner::backoff_multiplier`] after each failed attempt, up to
+    /// [`Runner::max_backoff`].
     ///
     /// Defaults to 100 milliseconds.
     pub fn connection_test_interval(mut self, connection_test_interval: Duration) -> Self {
@@ -69,6 +142,98 @@ impl Runner {
         self
     }
 
+    /// Set the factor the delay between connection attempts is multiplied by after each
+    /// failed attempt.
+    ///
+    /// Defaults to 1.5.
+    pub fn backoff_multiplier(mut self, backoff_multiplier: f64) -> Self {
+        self.backoff_multiplier = backoff_multiplier;
+        self
+    }
+
+    /// Set the maximum delay between connection attempts.
+    ///
+    /// Defaults to 500 milliseconds.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set a SQL script to run against the database once it is ready, before the test is invoked.
+    /// Useful for creating tables, extensions or fixtures that every test body can rely on.
+    ///
+    /// The test will fail if the script fails to execute.
+    pub fn init_sql(mut self, init_sql: impl Into<String>) -> Self {
+        self.init_sql = Some(init_sql.into());
+        self
+    }
+
+    /// Like [`Runner::init_sql`], but reads the script from a file.
+    ///
+    /// The file is read eagerly, so the test will fail immediately if it cannot be read.
+    pub fn init_sql_file(self, path: impl AsRef<Path>) -> Self {
+        let init_sql = fs::read_to_string(path.as_ref()).unwrap_or_else(|err| {
+            panic!(
+                "Should be able to read init SQL file {}: {err}",
+                path.as_ref().display()
+            )
+        });
+        self.init_sql(init_sql)
+    }
+
+    /// Set the postgres user to create and connect as.
+    ///
+    /// Defaults to `postgres`.
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = user.into();
+        self
+    }
+
+    /// Set the password for the postgres user.
+    ///
+    /// Defaults to `postgres`.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = password.into();
+        self
+    }
+
+    /// Set the database to create and connect to.
+    ///
+    /// Defaults to `postgres`.
+    pub fn database(mut self, database: impl Into<String>) -> Self {
+        self.database = database.into();
+        self
+    }
+
+    /// Set whether connections to the database should be made over TLS.
+    ///
+    /// When enabled, the container generates a self-signed certificate at startup
+    /// and the produced url has `sslmode=require` set. Readiness polling performs
+    /// the same TLS handshake, so a successful `run` guarantees TLS is actually up.
+    ///
+    /// Defaults to [`TlsMode::Disabled`].
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Set how `Runner` decides the container is ready to be polled for a connection.
+    ///
+    /// Defaults to [`Readiness::Connection`].
+    pub fn readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Set the maximum number of connections in the pool handed to
+    /// [`Runner::run_with_pool`].
+    ///
+    /// Defaults to 10.
+    pub fn pool_max_size(mut self, pool_max_size: u32) -> Self {
+        self.pool_max_size = pool_max_size;
+        self
+    }
+
     /// Run the test.
     /// The test will be passed a postgres connection url.
     /// The test will fail if the connection is not established within the connection timeout.
@@ -80,19 +245,34 @@ impl Runner {
     {
         let mut test = DockerTest::new().with_default_source(dockertest::Source::DockerHub);
 
-        let image = Image::with_repository("postgres").tag(self.container_tag);
+        let image = Image::with_repository(self.repository).tag(self.container_tag);
+        let mut env: std::collections::HashMap<String, String> = [
+            ("POSTGRES_USER".to_string(), self.user.clone()),
+            ("POSTGRES_PASSWORD".to_string(), self.password.clone()),
+            ("POSTGRES_DB".to_string(), self.database.clone()),
+        ]
+        .into();
+        env.extend(self.extra_env.iter().cloned());
+
         let mut composition = Composition::with_image(image)
-            .with_env(
-                [(
-                    "POSTGRES_PASSWORD".to_string(),
-                    POSTGRES_PASSWORD.to_string(),
-                )]
-                .into(),
-            )
-            .with_wait_for(Box::new(RunningWait {
-                check_interval: 1,
-                max_checks: self.container_timeout.as_secs(),
-            }));
+            .with_container_name("postgres")
+            .with_env(env)
+            .with_wait_for(match self.readiness {
+                Readiness::Connection => Box::new(RunningWait {
+                    check_interval: 1,
+                    max_checks: self.container_timeout.as_secs(),
+                }) as Box<dyn dockertest::waitfor::WaitFor>,
+                Readiness::LogMessage => Box::new(LogMessageWait {
+                    timeout: self.container_timeout,
+                }),
+            });
+        if self.tls == TlsMode::Enabled {
+            composition = composition.with_cmd(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                ENABLE_TLS_CMD.to_string(),
+            ]);
+        }
         composition.publish_all_ports();
         test.add_composition(composition);
 
@@ -100,9 +280,16 @@ impl Runner {
             let url = {
                 let handle = ops.handle("postgres");
                 let (ip, port) = handle
-                    .host_port(5432)
-                    .expect("Should have port 5432 mapped");
-                format!("postgresql://postgres:{POSTGRES_PASSWORD}@{ip}:{port}/postgres")
+                    .host_port(self.ready_port)
+                    .expect("Should have the ready port mapped");
+                let user = percent_encode(&self.user);
+                let password = percent_encode(&self.password);
+                let database = percent_encode(&self.database);
+                let mut url = format!("postgresql://{user}:{password}@{ip}:{port}/{database}");
+                if self.tls == TlsMode::Enabled {
+                    url.push_str("?sslmode=require");
+                }
+                url
             };
 
             let has_timed_out = block_on(async {
@@ -115,7 +302,12 @@ impl Runner {
             let res = if has_timed_out {
                 Ok(())
             } else {
-                panic::catch_unwind(|| block_on(f(url)))
+                panic::catch_unwind(|| {
+                    if let Some(init_sql) = &self.init_sql {
+                        block_on(self.run_init_sql(&url, init_sql));
+                    }
+                    block_on(f(url))
+                })
             };
 
             async move {
@@ -132,23 +324,99 @@ impl Runner {
         });
     }
 
+    /// Run the test like [`Runner::run`], but instead of a bare connection url, the test
+    /// is passed a connection pool already built against the ready database.
+    ///
+    /// The test will fail if the pool cannot be built.
+    pub fn run_with_pool<T, Fut>(self, f: T)
+    where
+        T: FnOnce(Pool<PostgresConnectionManager<Connector>>) -> Fut + UnwindSafe + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let tls = self.tls;
+        let pool_max_size = self.pool_max_size;
+
+        self.run(move |url| async move {
+            let config = url
+                .parse()
+                .expect("Should be able to parse the connection url");
+            let manager = PostgresConnectionManager::new(config, Connector::new(tls));
+            let pool = Pool::builder()
+                .max_size(pool_max_size)
+                .build(manager)
+                .await
+                .expect("Should be able to build the connection pool");
+
+            f(pool).await;
+        });
+    }
+
     async fn wait_for_connection(&self, url: &str) {
+        let mut delay = self.connection_test_interval;
         loop {
-            if tokio_postgres::connect(url, tokio_postgres::NoTls)
-                .await
-                .is_ok()
-            {
-                break;
+            match tokio_postgres::connect(url, Connector::new(self.tls)).await {
+                Ok(_) => break,
+                Err(err) if is_fatal_auth_error(&err) => {
+                    panic!("Authentication with postgres failed: {err}");
+                }
+                Err(_) => {}
             }
-            sleep(self.connection_test_interval).await;
+
+            sleep(Self::jitter(delay)).await;
+            delay = next_backoff_delay(delay, self.backoff_multiplier, self.max_backoff);
         }
     }
+
+    /// Apply up to ±20% random jitter to a delay, to avoid thundering-herd sleeps when
+    /// multiple containers are coming up at the same time.
+    fn jitter(delay: Duration) -> Duration {
+        let factor = rand::thread_rng().gen_range(0.8..=1.2);
+        delay.mul_f64(factor)
+    }
+
+    /// Run the configured init SQL script against the given url.
+    /// Panics if the script fails to execute.
+    async fn run_init_sql(&self, url: &str, init_sql: &str) {
+        let (client, connection) = tokio_postgres::connect(url, Connector::new(self.tls))
+            .await
+            .expect("Should be able to connect to run init SQL");
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("connection error while running init SQL: {}", e);
+            }
+        });
+
+        client
+            .batch_execute(init_sql)
+            .await
+            .expect("Should be able to run init SQL");
+    }
 }
 
 fn block_on<F: Future>(future: F) -> F::Output {
     task::block_in_place(|| Handle::current().block_on(future))
 }
 
+/// Percent-encode a user-supplied connection url component (user, password or database
+/// name) so that characters like `@ : / ?` can't be used to smuggle extra url parts in.
+fn percent_encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+/// Grow a backoff delay by `multiplier`, capped at `max`.
+fn next_backoff_delay(delay: Duration, multiplier: f64, max: Duration) -> Duration {
+    Duration::from_secs_f64((delay.as_secs_f64() * multiplier).min(max.as_secs_f64()))
+}
+
+/// Whether a connection error is a fatal authorization failure (SqlState class `28`,
+/// "Invalid Authorization Specification" — a bad password, or a role/database that
+/// doesn't exist) rather than a transient one like "connection refused" while the
+/// container is still starting up.
+fn is_fatal_auth_error(err: &tokio_postgres::Error) -> bool {
+    err.code().is_some_and(|code| code.code().starts_with("28"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,4 +447,188 @@ mod tests {
                 assert_eq!(sum, 3);
             });
     }
+
+    #[test_log::test]
+    fn it_can_use_log_message_readiness() {
+        Runner::new()
+            .readiness(Readiness::LogMessage)
+            .run(|url| async move {
+                let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                    .await
+                    .expect("Should be able to connect once the ready log line is seen twice");
+
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("connection error: {}", e);
+                    }
+                });
+
+                let rows = client
+                    .query("SELECT 1", &[])
+                    .await
+                    .expect("Error running query");
+
+                assert_eq!(rows.len(), 1);
+            });
+    }
+
+    #[test]
+    fn percent_encode_escapes_url_special_characters() {
+        assert_eq!(percent_encode("p@ss:w/o?rd"), "p%40ss%3Aw%2Fo%3Frd");
+    }
+
+    #[test_log::test]
+    fn it_can_use_a_password_containing_url_special_characters() {
+        Runner::new()
+            .password("p@ss:w/o?rd")
+            .run(|url| async move {
+                let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                    .await
+                    .expect("Should be able to connect with the percent-encoded password");
+
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("connection error: {}", e);
+                    }
+                });
+
+                let rows = client
+                    .query("SELECT 1", &[])
+                    .await
+                    .expect("Error running query");
+
+                assert_eq!(rows.len(), 1);
+            });
+    }
+
+    #[test_log::test]
+    fn it_runs_init_sql_before_the_test_body() {
+        Runner::new()
+            .init_sql("CREATE TABLE greeting (message TEXT NOT NULL)")
+            .run(|url| async move {
+                let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                    .await
+                    .unwrap();
+
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("connection error: {}", e);
+                    }
+                });
+
+                client
+                    .execute(
+                        "INSERT INTO greeting (message) VALUES ($1)",
+                        &[&"hello".to_string()],
+                    )
+                    .await
+                    .expect("Table from init_sql should already exist");
+
+                let rows = client
+                    .query("SELECT message FROM greeting", &[])
+                    .await
+                    .expect("Error running query");
+
+                assert_eq!(rows.len(), 1);
+                let message: String = rows[0].get(0);
+                assert_eq!(message, "hello");
+            });
+    }
+
+    #[test_log::test]
+    fn it_can_connect_over_tls() {
+        Runner::new().tls(TlsMode::Enabled).run(|url| async move {
+            assert!(url.contains("sslmode=require"));
+
+            let connector = Connector::new(TlsMode::Enabled);
+            let (client, connection) = tokio_postgres::connect(&url, connector)
+                .await
+                .expect("Should be able to complete a TLS handshake");
+
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    eprintln!("connection error: {}", e);
+                }
+            });
+
+            let rows = client
+                .query("SELECT 1", &[])
+                .await
+                .expect("Error running query");
+
+            assert_eq!(rows.len(), 1);
+        });
+    }
+
+    #[test_log::test]
+    fn it_can_hand_the_test_a_connection_pool() {
+        Runner::new().run_with_pool(|pool| async move {
+            let conn = pool
+                .get()
+                .await
+                .expect("Should be able to get a connection from the pool");
+
+            let rows = conn
+                .query("SELECT 1 + 2", &[])
+                .await
+                .expect("Error running query");
+
+            assert_eq!(rows.len(), 1);
+
+            let sum: i32 = rows[0].get(0);
+            assert_eq!(sum, 3);
+        });
+    }
+
+    #[test]
+    fn jitter_stays_within_twenty_percent_of_the_delay() {
+        let delay = Duration::from_millis(100);
+        for _ in 0..100 {
+            let jittered = Runner::jitter(delay);
+            assert!(jittered >= Duration::from_millis(80));
+            assert!(jittered <= Duration::from_millis(120));
+        }
+    }
+
+    #[test]
+    fn next_backoff_delay_grows_and_caps_at_max() {
+        let max = Duration::from_millis(500);
+
+        let delay = next_backoff_delay(Duration::from_millis(100), 1.5, max);
+        assert_eq!(delay, Duration::from_millis(150));
+
+        let delay = next_backoff_delay(Duration::from_millis(400), 1.5, max);
+        assert_eq!(delay, max);
+    }
+
+    #[test_log::test]
+    fn it_can_use_a_non_default_image_env_and_port() {
+        // `timescale/timescaledb` extends the official postgres image and honors the same
+        // `POSTGRES_USER`/`POSTGRES_PASSWORD`/`POSTGRES_DB` env vars, so this doubles as a
+        // regression test for the `ops.handle` lookup, which used to be hardcoded to the
+        // `postgres` repository name and broke for any other image.
+        Runner::new()
+            .image("timescale/timescaledb")
+            .container_tag("latest-pg15")
+            .ready_port(5432)
+            .env("TS_TUNE_MEMORY", "1GB")
+            .run(|url| async move {
+                let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                    .await
+                    .expect("Should be able to connect to the non-default image");
+
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        eprintln!("connection error: {}", e);
+                    }
+                });
+
+                let rows = client
+                    .query("SELECT 1", &[])
+                    .await
+                    .expect("Error running query");
+
+                assert_eq!(rows.len(), 1);
+            });
+    }
 }
@@ -0,0 +1,153 @@
+use std::{
+    error::Error as StdError,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect, TlsStream};
+
+/// Selects how a connection to the database should be secured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Connect in plaintext. This is the default.
+    #[default]
+    Disabled,
+    /// Connect over TLS, trusting the self-signed certificate the container
+    /// generates for itself at startup.
+    Enabled,
+}
+
+/// A [`MakeTlsConnect`] that is either a no-op or backed by `postgres-native-tls`,
+/// picked at runtime from a [`TlsMode`]. This lets `Runner` share the exact same
+/// connection code path for readiness polling, init SQL and the connection handed
+/// to the test, regardless of whether TLS is enabled.
+#[derive(Clone)]
+pub enum Connector {
+    NoTls(tokio_postgres::NoTls),
+    NativeTls(postgres_native_tls::MakeTlsConnector),
+}
+
+impl Connector {
+    pub fn new(mode: TlsMode) -> Self {
+        match mode {
+            TlsMode::Disabled => Self::NoTls(tokio_postgres::NoTls),
+            TlsMode::Enabled => {
+                let connector = native_tls::TlsConnector::builder()
+                    // The container generates a fresh self-signed certificate on
+                    // every run, so there is no CA available to validate it against.
+                    .danger_accept_invalid_certs(true)
+                    .build()
+                    .expect("Should be able to build a TLS connector");
+                Self::NativeTls(postgres_native_tls::MakeTlsConnector::new(connector))
+            }
+        }
+    }
+}
+
+impl<S> MakeTlsConnect<S> for Connector
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    type Stream = MaybeTlsStream<S>;
+    type TlsConnect = MaybeTlsConnect<S>;
+    type Error = Box<dyn StdError + Sync + Send>;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<Self::TlsConnect, Self::Error> {
+        match self {
+            Self::NoTls(no_tls) => Ok(MaybeTlsConnect::NoTls(no_tls.make_tls_connect(domain)?)),
+            Self::NativeTls(make_tls) => Ok(MaybeTlsConnect::NativeTls(
+                make_tls.make_tls_connect(domain)?,
+            )),
+        }
+    }
+}
+
+pub enum MaybeTlsConnect<S> {
+    NoTls(<tokio_postgres::NoTls as MakeTlsConnect<S>>::TlsConnect),
+    NativeTls(<postgres_native_tls::MakeTlsConnector as MakeTlsConnect<S>>::TlsConnect),
+}
+
+impl<S> TlsConnect<S> for MaybeTlsConnect<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    type Stream = MaybeTlsStream<S>;
+    type Error = Box<dyn StdError + Sync + Send>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn connect(self) -> Self::Future {
+        Box::pin(async move {
+            match self {
+                Self::NoTls(connect) => Ok(MaybeTlsStream::NoTls(connect.connect().await?)),
+                Self::NativeTls(connect) => {
+                    Ok(MaybeTlsStream::NativeTls(connect.connect().await?))
+                }
+            }
+        })
+    }
+}
+
+pub enum MaybeTlsStream<S> {
+    NoTls(<tokio_postgres::NoTls as MakeTlsConnect<S>>::Stream),
+    NativeTls(<postgres_native_tls::MakeTlsConnector as MakeTlsConnect<S>>::Stream),
+}
+
+impl<S> AsyncRead for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::NoTls(s) => Pin::new(s).poll_read(cx, buf),
+            Self::NativeTls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::NoTls(s) => Pin::new(s).poll_write(cx, buf),
+            Self::NativeTls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::NoTls(s) => Pin::new(s).poll_flush(cx),
+            Self::NativeTls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::NoTls(s) => Pin::new(s).poll_shutdown(cx),
+            Self::NativeTls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+impl<S> TlsStream for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn channel_binding(&self) -> ChannelBinding {
+        match self {
+            Self::NoTls(s) => s.channel_binding(),
+            Self::NativeTls(s) => s.channel_binding(),
+        }
+    }
+}